@@ -0,0 +1,500 @@
+use std::error::Error;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::ops::RangeInclusive;
+use std::os::unix::fs::FileExt;
+
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
+
+use {KeyType, ValueType, FILE_HEADER, CURRENT_VERSION, NUM_CHILDREN};
+use wal_file::KeyValuePair;
+
+/// A node in the on-disk B+Tree. Leaves hold the actual `KeyValuePair`s;
+/// internal nodes hold one separator key per child (the child's smallest
+/// key) alongside that child's byte offset in the file.
+#[derive(Clone)]
+enum Node<K: KeyType, V: ValueType> {
+    Leaf(Vec<KeyValuePair<K, V>>),
+    Internal(Vec<K>, Vec<u64>),
+}
+
+impl<K: KeyType, V: ValueType> Encodable for Node<K, V> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_enum("Node", |s| {
+            match *self {
+                Node::Leaf(ref entries) => {
+                    s.emit_enum_variant("Leaf", 0, 1, |s| {
+                        s.emit_enum_variant_arg(0, |s| entries.encode(s))
+                    })
+                },
+                Node::Internal(ref keys, ref children) => {
+                    s.emit_enum_variant("Internal", 1, 2, |s| {
+                        (s.emit_enum_variant_arg(0, |s| keys.encode(s)))?;
+                        s.emit_enum_variant_arg(1, |s| children.encode(s))
+                    })
+                },
+            }
+        })
+    }
+}
+
+impl<K: KeyType, V: ValueType> Decodable for Node<K, V> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Node<K, V>, D::Error> {
+        d.read_enum("Node", |d| {
+            d.read_enum_variant(&["Leaf", "Internal"], |d, idx| {
+                match idx {
+                    0 => {
+                        let entries = (d.read_enum_variant_arg(0, |d| Decodable::decode(d)))?;
+                        Ok(Node::Leaf(entries))
+                    },
+                    1 => {
+                        let keys = (d.read_enum_variant_arg(0, |d| Decodable::decode(d)))?;
+                        let children = (d.read_enum_variant_arg(1, |d| Decodable::decode(d)))?;
+                        Ok(Node::Internal(keys, children))
+                    },
+                    _ => unreachable!(),
+                }
+            })
+        })
+    }
+}
+
+/// Every committed root lives at a `PAGE_SIZE`-aligned offset, so a reader
+/// can always land exactly on a page boundary while scanning backward for
+/// the most recent valid commit.
+const PAGE_SIZE: u64 = 4096;
+
+/// Precedes every commit page; distinguishes a real commit from whatever
+/// bytes happen to follow the live data (e.g. a half-written next commit).
+const COMMIT_MAGIC: [u8; 3] = *b"RTC";
+
+const PAGE_TAG_EMPTY: u8 = 0;
+const PAGE_TAG_ROOT: u8 = 1;
+
+/// The B+Tree as it sits on disk: a header, followed by an append-only run
+/// of length-prefixed nodes, with the current root committed as a
+/// length-prefixed chunk at the start of its own `PAGE_SIZE`-aligned page.
+///
+/// A compaction only ever appends: it writes new leaf/internal nodes after
+/// the current end of the file, then commits by padding to the next page
+/// boundary and writing the new root there. Until that commit page is
+/// written, the previous root (and everything reachable from it) is still
+/// exactly as it was, so a crash mid-compaction leaves the live tree intact
+/// instead of a half-written file needing a rename to recover from.
+pub struct OnDiskBTree<K: KeyType, V: ValueType> {
+    file: File,
+    max_key_size: usize,
+    max_value_size: usize,
+    root: Option<Node<K, V>>,
+    _marker: ::std::marker::PhantomData<(K, V)>,
+}
+
+impl<K: KeyType, V: ValueType> OnDiskBTree<K, V> {
+    pub fn new(file_path: String, max_key_size: usize, max_value_size: usize) -> Result<OnDiskBTree<K, V>, Box<dyn Error>> {
+        let is_new = !::std::path::Path::new(&file_path).exists();
+
+        let mut file = (OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&file_path))?;
+
+        let root = if is_new {
+            (file.write_all(FILE_HEADER.as_bytes()))?;
+            (file.write_all(&[CURRENT_VERSION]))?;
+            None
+        } else {
+            let len = (file.metadata())?.len();
+            find_committed_root(&file, len)
+        };
+
+        Ok(OnDiskBTree{file: file, max_key_size: max_key_size, max_value_size: max_value_size, root: root, _marker: ::std::marker::PhantomData})
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, Box<dyn Error>> {
+        Ok((self.file.metadata())?)
+    }
+
+    /// Returns every entry in the tree, in ascending key order, reading
+    /// nodes from disk lazily as the traversal descends into them rather
+    /// than buffering the whole tree up front. Reads through an
+    /// independent file handle (see `OnDiskBTreeIterator`), so the
+    /// iterator can stay alive across a mutating call like
+    /// `compact_from_sorted`.
+    pub fn iter(&self) -> Result<OnDiskBTreeIterator<K, V>, Box<dyn Error>> {
+        let file = (self.file.try_clone())?;
+
+        Ok(OnDiskBTreeIterator::new(file, self.root.clone(), None, None))
+    }
+
+    /// Returns every entry whose key falls within `bounds`, in ascending
+    /// key order, descending only into the subtrees that can overlap the
+    /// requested range and reading nodes from disk lazily as it goes.
+    pub fn range(&self, bounds: RangeInclusive<K>) -> Result<OnDiskBTreeIterator<K, V>, Box<dyn Error>> {
+        let (start, end) = bounds.into_inner();
+        let file = (self.file.try_clone())?;
+
+        Ok(OnDiskBTreeIterator::new(file, self.root.clone(), Some(start), Some(end)))
+    }
+
+    /// Folds a sequence of entries, already sorted in ascending `(key,
+    /// value)` order, into the tree: `NUM_CHILDREN`-wide leaf nodes are
+    /// appended to disk as soon as they fill up, and likewise for each
+    /// internal level built on top of them, so memory usage stays bounded
+    /// by the tree's height rather than growing with the dataset. Only the
+    /// final root is held back from being written, since it is embedded
+    /// directly into the commit page instead of being referenced by
+    /// offset. Used by `compact()` to fold the memtable and the existing
+    /// tree into one.
+    pub fn compact_from_sorted<I: Iterator<Item = KeyValuePair<K, V>>>(&mut self, entries: I) -> Result<(), Box<dyn Error>> {
+        (self.file.seek(SeekFrom::End(0)))?;
+
+        let key_limit = SizeLimit::Bounded(self.max_key_size as u64);
+        let value_limit = SizeLimit::Bounded(self.max_value_size as u64);
+
+        let mut leaf_buffer: Vec<KeyValuePair<K, V>> = Vec::with_capacity(NUM_CHILDREN);
+        let mut levels: Vec<Vec<(K, u64)>> = Vec::new();
+
+        for entry in entries {
+            // reject oversized keys/values at the disk layer too, mirroring
+            // the SizeLimit::Bounded check WALFile::write_record applies
+            (encode(&entry.key, key_limit))?;
+            (encode(&entry.value, value_limit))?;
+
+            leaf_buffer.push(entry);
+
+            if leaf_buffer.len() == NUM_CHILDREN {
+                let chunk = ::std::mem::replace(&mut leaf_buffer, Vec::with_capacity(NUM_CHILDREN));
+                (flush_leaf(&mut self.file, &mut levels, chunk))?;
+            }
+        }
+
+        let root = if levels.is_empty() {
+            // everything fit in a single leaf: embed it directly as the
+            // root instead of writing it out and immediately reading it back
+            if leaf_buffer.is_empty() { None } else { Some(Node::Leaf(leaf_buffer)) }
+        } else {
+            if !leaf_buffer.is_empty() {
+                (flush_leaf(&mut self.file, &mut levels, leaf_buffer))?;
+            }
+
+            Some((collapse_levels(&mut self.file, levels))?)
+        };
+
+        self.commit(root)
+    }
+
+    /// Pads the file to the next `PAGE_SIZE` boundary and writes `root`
+    /// there as the new committed root, fsyncing before returning so the
+    /// commit is durable the moment this call succeeds.
+    fn commit(&mut self, root: Option<Node<K, V>>) -> Result<(), Box<dyn Error>> {
+        let len = (self.file.seek(SeekFrom::End(0)))?;
+        let page_offset = len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let pad = (page_offset - len) as usize;
+
+        if pad > 0 {
+            (self.file.write_all(&vec![0u8; pad]))?;
+        }
+
+        (self.file.write_all(&COMMIT_MAGIC))?;
+
+        match root {
+            Some(ref node) => {
+                let encoded = (encode(node, SizeLimit::Infinite))?;
+
+                (self.file.write_all(&[PAGE_TAG_ROOT]))?;
+                (self.file.write_all(&u64_to_bytes(encoded.len() as u64)))?;
+                (self.file.write_all(&encoded))?;
+            },
+            None => {
+                (self.file.write_all(&[PAGE_TAG_EMPTY]))?;
+            },
+        }
+
+        (self.file.sync_all())?;
+
+        self.root = root;
+
+        Ok(())
+    }
+}
+
+/// Reads and decodes the length-prefixed node at `offset`.
+fn read_node<K: KeyType, V: ValueType>(file: &File, offset: u64) -> Result<Node<K, V>, Box<dyn Error>> {
+    let mut len_bytes = [0u8; 8];
+    (file.read_exact_at(&mut len_bytes, offset))?;
+    let len = u64_from_bytes(&len_bytes);
+
+    let mut buf = vec![0u8; len as usize];
+    (file.read_exact_at(&mut buf, offset + 8))?;
+
+    Ok((decode(&buf))?)
+}
+
+/// Appends a length-prefixed, bincode-encoded node to the end of `file`,
+/// returning the byte offset it was written at.
+fn append_node<K: KeyType, V: ValueType>(file: &mut File, node: &Node<K, V>) -> Result<u64, Box<dyn Error>> {
+    let offset = (file.seek(SeekFrom::End(0)))?;
+
+    let encoded = (encode(node, SizeLimit::Infinite))?;
+
+    (file.write_all(&u64_to_bytes(encoded.len() as u64)))?;
+    (file.write_all(&encoded))?;
+
+    Ok(offset)
+}
+
+/// Writes `chunk` out as a leaf node and pushes it onto level 0 as a child
+/// of the internal level being built above it.
+fn flush_leaf<K: KeyType, V: ValueType>(file: &mut File, levels: &mut Vec<Vec<(K, u64)>>, chunk: Vec<KeyValuePair<K, V>>) -> Result<(), Box<dyn Error>> {
+    let min_key = chunk[0].key.clone();
+    let offset = (append_node(file, &Node::Leaf(chunk)))?;
+
+    push_child::<K, V>(file, levels, 0, min_key, offset)
+}
+
+/// Pushes a child's `(separator key, offset)` onto `levels[level_idx]`
+/// (creating the level if this is its first child). If that fills the
+/// level to `NUM_CHILDREN`, the level is immediately packed into an
+/// internal node, written out, and pushed as a child of the level above --
+/// recursing upward the same way a carry propagates when incrementing a
+/// counter. This keeps at most one partial, sub-`NUM_CHILDREN` buffer per
+/// level in memory at any time, rather than a whole level's worth of nodes.
+fn push_child<K: KeyType, V: ValueType>(file: &mut File, levels: &mut Vec<Vec<(K, u64)>>, level_idx: usize, key: K, offset: u64) -> Result<(), Box<dyn Error>> {
+    if level_idx == levels.len() {
+        levels.push(Vec::with_capacity(NUM_CHILDREN));
+    }
+
+    levels[level_idx].push((key, offset));
+
+    if levels[level_idx].len() == NUM_CHILDREN {
+        let chunk = ::std::mem::replace(&mut levels[level_idx], Vec::with_capacity(NUM_CHILDREN));
+        let min_key = chunk[0].0.clone();
+        let keys: Vec<K> = chunk.iter().map(|(k, _)| k.clone()).collect();
+        let children: Vec<u64> = chunk.iter().map(|&(_, o)| o).collect();
+
+        let offset = (append_node(file, &Node::<K, V>::Internal(keys, children)))?;
+
+        (push_child::<K, V>(file, levels, level_idx + 1, min_key, offset))?;
+    }
+
+    Ok(())
+}
+
+/// Collapses whatever partial levels remain once the entry stream is
+/// exhausted into a single root node: every level but the topmost is
+/// written out as an ordinary internal node (so the level above it can
+/// reference it by offset), while the topmost level's node is returned
+/// directly so the caller can embed it in the commit page instead of
+/// writing it and then immediately reading it back.
+fn collapse_levels<K: KeyType, V: ValueType>(file: &mut File, mut levels: Vec<Vec<(K, u64)>>) -> Result<Node<K, V>, Box<dyn Error>> {
+    let top = levels.len() - 1;
+
+    for level_idx in 0..levels.len() {
+        let items = ::std::mem::take(&mut levels[level_idx]);
+
+        if items.is_empty() {
+            continue;
+        }
+
+        let keys: Vec<K> = items.iter().map(|(k, _)| k.clone()).collect();
+        let children: Vec<u64> = items.iter().map(|&(_, o)| o).collect();
+        let node = Node::Internal(keys, children);
+
+        if level_idx == top {
+            return Ok(node);
+        }
+
+        let min_key = items[0].0.clone();
+        let offset = (append_node(file, &node))?;
+
+        levels[level_idx + 1].push((min_key, offset));
+    }
+
+    unreachable!("the topmost level always still holds the last node it was pushed")
+}
+
+/// Scans backward one page at a time from the end of the file, looking for
+/// the most recent page that starts with `COMMIT_MAGIC` and decodes
+/// successfully. Returns `None` if no valid commit page exists at all (a
+/// brand-new tree) -- it never treats a failed decode as an empty tree,
+/// since an older valid commit may still be sitting on an earlier page.
+fn find_committed_root<K: KeyType, V: ValueType>(file: &File, file_len: u64) -> Option<Node<K, V>> {
+    let header_len = (FILE_HEADER.len() + size_of::<u8>()) as u64;
+
+    let mut page_offset = (file_len / PAGE_SIZE) * PAGE_SIZE;
+
+    loop {
+        if page_offset < header_len {
+            return None;
+        }
+
+        match read_commit_page(file, page_offset) {
+            Ok(root) => return root,
+            Err(()) => page_offset -= PAGE_SIZE,
+        }
+    }
+}
+
+/// Tries to read a commit page at exactly `offset`. `Err(())` means this
+/// page is not a valid commit (wrong magic, corrupt length, or a torn
+/// write) and the caller should keep scanning backward.
+fn read_commit_page<K: KeyType, V: ValueType>(file: &File, offset: u64) -> Result<Option<Node<K, V>>, ()> {
+    let mut magic = [0u8; 3];
+    (file.read_exact_at(&mut magic, offset)).map_err(|_| ())?;
+
+    if magic != COMMIT_MAGIC {
+        return Err(());
+    }
+
+    let mut tag = [0u8; 1];
+    (file.read_exact_at(&mut tag, offset + 3)).map_err(|_| ())?;
+
+    match tag[0] {
+        PAGE_TAG_EMPTY => Ok(None),
+        PAGE_TAG_ROOT => {
+            let mut len_bytes = [0u8; 8];
+            (file.read_exact_at(&mut len_bytes, offset + 4)).map_err(|_| ())?;
+            let len = u64_from_bytes(&len_bytes);
+
+            let mut buf = vec![0u8; len as usize];
+            (file.read_exact_at(&mut buf, offset + 4 + 8)).map_err(|_| ())?;
+
+            decode(&buf).map(Some).map_err(|_| ())
+        },
+        _ => Err(()),
+    }
+}
+
+fn u64_to_bytes(value: u64) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = ((value >> (8 * i)) & 0xff) as u8;
+    }
+
+    bytes
+}
+
+fn u64_from_bytes(bytes: &[u8]) -> u64 {
+    let mut value: u64 = 0;
+
+    for (i, &byte) in bytes.iter().enumerate().take(8) {
+        value |= (byte as u64) << (8 * i);
+    }
+
+    value
+}
+
+/// One level of an in-progress depth-first descent through the on-disk
+/// tree: either a leaf's remaining entries, or an internal node's children
+/// not yet visited.
+enum Frame<K: KeyType, V: ValueType> {
+    Leaf(::std::vec::IntoIter<KeyValuePair<K, V>>),
+    Internal{keys: Vec<K>, children: Vec<u64>, next: usize},
+}
+
+fn push_frame<K: KeyType, V: ValueType>(stack: &mut Vec<Frame<K, V>>, node: Node<K, V>) {
+    match node {
+        Node::Leaf(entries) => stack.push(Frame::Leaf(entries.into_iter())),
+        Node::Internal(keys, children) => stack.push(Frame::Internal{keys: keys, children: children, next: 0}),
+    }
+}
+
+/// What `OnDiskBTreeIterator::next` should do once it has inspected (and
+/// released its borrow of) the top stack frame.
+enum Step<K: KeyType, V: ValueType> {
+    Pop,
+    Continue,
+    Descend(u64),
+    Yield(KeyValuePair<K, V>),
+}
+
+/// Walks an `OnDiskBTree` in ascending key order (optionally bounded to a
+/// `[start, end]` range), reading one node from disk at a time as the
+/// traversal descends rather than collecting the whole tree up front. The
+/// stack holds at most one frame per level of tree height, so peak memory
+/// is bounded by the tree's depth, not its size.
+///
+/// Reads through its own cloned file handle rather than borrowing the
+/// `OnDiskBTree` it was created from, so it can stay alive (and keep
+/// reading the tree as it stood when created) across a later mutating
+/// call like `compact_from_sorted` on the same tree.
+pub struct OnDiskBTreeIterator<K: KeyType, V: ValueType> {
+    file: File,
+    stack: Vec<Frame<K, V>>,
+    start: Option<K>,
+    end: Option<K>,
+}
+
+impl<K: KeyType, V: ValueType> OnDiskBTreeIterator<K, V> {
+    fn new(file: File, root: Option<Node<K, V>>, start: Option<K>, end: Option<K>) -> OnDiskBTreeIterator<K, V> {
+        let mut stack = Vec::new();
+
+        if let Some(root) = root {
+            push_frame(&mut stack, root);
+        }
+
+        OnDiskBTreeIterator{file: file, stack: stack, start: start, end: end}
+    }
+}
+
+impl<K: KeyType, V: ValueType> Iterator for OnDiskBTreeIterator<K, V> {
+    type Item = KeyValuePair<K, V>;
+
+    fn next(&mut self) -> Option<KeyValuePair<K, V>> {
+        loop {
+            let step = match self.stack.last_mut() {
+                None => return None,
+                Some(Frame::Leaf(iter)) => {
+                    match iter.next() {
+                        Some(item) => Step::Yield(item),
+                        None => Step::Pop,
+                    }
+                },
+                Some(Frame::Internal{keys, children, next}) => {
+                    if *next >= children.len() {
+                        Step::Pop
+                    } else {
+                        let i = *next;
+                        *next += 1;
+
+                        // children are laid out in ascending key order, so once a
+                        // child's minimum key is past `end` none of its siblings can match
+                        if self.end.as_ref().is_some_and(|end| &keys[i] > end) {
+                            Step::Pop
+                        } else {
+                            // a child spans [keys[i], keys[i+1]] (a key with several values can
+                            // straddle two siblings); skip it unless that span can reach `start`
+                            let overlaps_start = self.start.as_ref().is_none_or(|start| keys.get(i + 1).is_none_or(|next_min| next_min >= start));
+
+                            if overlaps_start {
+                                Step::Descend(children[i])
+                            } else {
+                                Step::Continue
+                            }
+                        }
+                    }
+                },
+            };
+
+            match step {
+                Step::Pop => {
+                    self.stack.pop();
+                },
+                Step::Continue => {},
+                Step::Descend(offset) => {
+                    let child = read_node(&self.file, offset).expect("read on-disk btree node");
+                    push_frame(&mut self.stack, child);
+                },
+                Step::Yield(item) => {
+                    let past_start = self.start.as_ref().is_none_or(|start| &item.key >= start);
+                    let past_end = self.end.as_ref().is_none_or(|end| &item.key <= end);
+
+                    if past_start && past_end {
+                        return Some(item);
+                    }
+                },
+            }
+        }
+    }
+}
@@ -6,26 +6,19 @@ mod wal_file;
 mod multi_map;
 mod disk_btree;
 
-use wal_file::{KeyValuePair, WALFile, WALIterator};
-use multi_map::{MultiMap, MultiMapIterator};
-use disk_btree::{OnDiskBTree};
+use wal_file::{KeyValuePair, WALFile, WALRecord};
+use multi_map::{MultiMap, MultiMapIterator, MultiMapRangeIterator};
+use disk_btree::{OnDiskBTree, OnDiskBTreeIterator};
 
-use bincode::SizeLimit;
-use bincode::rustc_serialize::{encode, decode};
 use rustc_serialize::{Encodable, Decodable};
 
-use std::cmp::max;
-use std::convert::From;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeSet;
 use std::error::Error;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::{Read, Write, Seek, SeekFrom, ErrorKind};
-use std::mem::{size_of};
-use std::str;
+use std::iter::Peekable;
+use std::ops::RangeInclusive;
 
 const NUM_CHILDREN: usize = 32;
-const FILE_HEADER: &'static str = "B+Tree\0";
+const FILE_HEADER: &str = "B+Tree\0";
 const CURRENT_VERSION: u8 = 0x01;
 
 // specify the types for the keys & values
@@ -37,93 +30,458 @@ pub trait ValueType: Ord + Encodable + Decodable + Clone  {}
 impl<T> KeyType for T where T: Ord + Encodable + Decodable + Clone {}
 impl<T> ValueType for T where T: Ord + Encodable + Decodable + Clone {}
 
+/// Durability knobs for a `BTree`'s write-ahead log.
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// Use `File::sync_all` (data and metadata) instead of `File::sync_data`
+    /// when a sync is performed.
+    pub use_fsync: bool,
+    /// Sync the WAL after every `write_record`. If `false`, writes are
+    /// batched and only synced at `compact()` boundaries (once the data has
+    /// been folded into the synced, freshly-built tree file) or on an
+    /// explicit `BTree::flush`.
+    pub sync_every_write: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config{use_fsync: true, sync_every_write: false}
+    }
+}
+
+/// Thresholds past which the memtable is automatically folded into the
+/// on-disk tree by an implicit `compact()`. Either bound may be disabled
+/// by setting it to `None`; `estimated_bytes` is derived from the entry
+/// count times `max_key_size + max_value_size`, not an exact measurement.
+#[derive(Clone, Copy, Default)]
+pub struct MemtableLimit {
+    pub max_entries: Option<usize>,
+    pub max_estimated_bytes: Option<usize>,
+}
+
 /// This struct holds all the pieces of the BTree mechanism
 pub struct BTree<K: KeyType, V: ValueType> {
-    tree_file_path: String,         // the path to the tree file
     max_key_size: usize,            // the max size of the key in bytes
     max_value_size: usize,          // the max size of the value in bytes
     tree_file: OnDiskBTree<K,V>,    // the file backing the whole thing
     wal_file: WALFile<K,V>,         // write-ahead log for in-memory items
     mem_tree: MultiMap<K,V>,        // in-memory multi-map that gets merged with the on-disk BTree
+    deleted_keys: BTreeSet<K>,      // whole-key tombstones not yet folded away by compact()
+    deleted_values: MultiMap<K,V>,  // single-value tombstones not yet folded away by compact()
+    memtable_limit: MemtableLimit,  // thresholds that trigger an automatic compact()
 }
 
 impl <K: KeyType, V: ValueType> BTree<K, V> {
-    pub fn new(tree_file_path: String, max_key_size: usize, max_value_size: usize) -> Result<BTree<K,V>, Box<Error>> {
+    pub fn new(tree_file_path: String, max_key_size: usize, max_value_size: usize) -> Result<BTree<K,V>, Box<dyn Error>> {
+        Self::with_config(tree_file_path, max_key_size, max_value_size, Config::default())
+    }
+
+    /// Like `new`, but with explicit control over the WAL's durability policy.
+    pub fn with_config(tree_file_path: String, max_key_size: usize, max_value_size: usize, config: Config) -> Result<BTree<K,V>, Box<dyn Error>> {
+        Self::with_memtable_limit(tree_file_path, max_key_size, max_value_size, config, MemtableLimit::default())
+    }
+
+    /// Like `with_config`, but also bounds the memtable: once `memtable_limit`
+    /// is crossed by `insert`/`delete`/`delete_value`, `compact()` runs automatically.
+    pub fn with_memtable_limit(tree_file_path: String, max_key_size: usize, max_value_size: usize, config: Config, memtable_limit: MemtableLimit) -> Result<BTree<K,V>, Box<dyn Error>> {
         // create our in-memory multi-map
         let mut mem_tree = MultiMap::<K,V>::new();
+        let mut deleted_keys = BTreeSet::<K>::new();
+        let mut deleted_values = MultiMap::<K,V>::new();
 
         // construct the path to the WAL file for the in-memory multi-map
         let wal_file_path = tree_file_path.to_owned() + ".wal";
 
         // construct our WAL file
-        let mut wal_file = try!(WALFile::<K,V>::new(wal_file_path.to_owned(), max_key_size, max_value_size));
-
-        // if we have a WAL file, replay it into the mem_tree
-        if try!(wal_file.is_new()) {
-            for kv in &mut wal_file {
-                mem_tree.insert(kv.key, kv.value);
+        let mut wal_file = (WALFile::<K,V>::new(wal_file_path.to_owned(), max_key_size, max_value_size, config.use_fsync, config.sync_every_write))?;
+
+        // if the WAL already holds records from a previous run, replay
+        // them into the mem_tree so we pick up where we left off
+        if ! (wal_file.is_new())? {
+            for record in &mut wal_file {
+                match record {
+                    WALRecord::Put(kv) => { mem_tree.insert(kv.key, kv.value); },
+                    WALRecord::DeleteKey(key) => {
+                        mem_tree.remove_key(&key);
+                        deleted_keys.insert(key);
+                    },
+                    WALRecord::DeleteValue(kv) => {
+                        mem_tree.remove_value(&kv.key, &kv.value);
+                        deleted_values.insert(kv.key, kv.value);
+                    },
+                }
             }
         }
 
         // open the data file
-        let mut tree_file = try!(OnDiskBTree::<K,V>::new(tree_file_path.to_owned(), max_key_size, max_value_size));
+        let tree_file = (OnDiskBTree::<K,V>::new(tree_file_path, max_key_size, max_value_size))?;
+
+        Ok(BTree{max_key_size: max_key_size,
+                max_value_size: max_value_size,
+                tree_file: tree_file,
+                wal_file: wal_file,
+                mem_tree: mem_tree,
+                deleted_keys: deleted_keys,
+                deleted_values: deleted_values,
+                memtable_limit: memtable_limit})
+    }
+
+    /// The number of entries currently held in memory (the memtable proper,
+    /// plus any tombstones not yet folded away by `compact()`).
+    pub fn memtable_len(&self) -> usize {
+        self.mem_tree.len() + self.deleted_keys.len() + self.deleted_values.len()
+    }
+
+    /// An estimate, in bytes, of the memtable's current size, derived from
+    /// `memtable_len()` and `max_key_size`/`max_value_size`.
+    pub fn memtable_estimated_bytes(&self) -> usize {
+        self.memtable_len() * (self.max_key_size + self.max_value_size)
+    }
+
+    /// Runs `compact()` if either configured memtable threshold has been crossed.
+    fn maybe_compact(&mut self) -> Result<(), Box<dyn Error>> {
+        let over_entries = self.memtable_limit.max_entries.is_some_and(|limit| self.memtable_len() >= limit);
+        let over_bytes = self.memtable_limit.max_estimated_bytes.is_some_and(|limit| self.memtable_estimated_bytes() >= limit);
 
-        return Ok(BTree{tree_file_path: tree_file_path,
-                        max_key_size: max_key_size,
-                        max_value_size: max_value_size,
-                        tree_file: tree_file,
-                        wal_file: wal_file,
-                        mem_tree: mem_tree});
+        if over_entries || over_bytes {
+            (self.compact())?;
+        }
+
+        Ok(())
     }
 
     /// Inserts a key into the BTree
-    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<Error>> {
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
         let record = KeyValuePair{key: key, value: value};
 
-        try!(self.wal_file.write_record(&record));
+        (self.wal_file.write_record(&WALRecord::Put(record.clone())))?;
 
         let KeyValuePair{key, value} = record;
 
         self.mem_tree.insert(key, value);
 
+        (self.maybe_compact())?;
+
+        Ok( () )
+    }
+
+    /// Deletes every value stored under `key`.
+    pub fn delete(&mut self, key: K) -> Result<(), Box<dyn Error>> {
+        (self.wal_file.write_record(&WALRecord::DeleteKey(key.clone())))?;
+
+        self.mem_tree.remove_key(&key);
+        self.deleted_keys.insert(key);
+
+        (self.maybe_compact())?;
+
+        Ok( () )
+    }
+
+    /// Deletes a single `(key, value)` pair.
+    pub fn delete_value(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        let record = KeyValuePair{key: key, value: value};
+
+        (self.wal_file.write_record(&WALRecord::DeleteValue(record.clone())))?;
+
+        let KeyValuePair{key, value} = record;
+
+        self.mem_tree.remove_value(&key, &value);
+        self.deleted_values.insert(key, value);
+
+        (self.maybe_compact())?;
+
         Ok( () )
     }
 
-/*
-    /// Merges the records on disk with the records in memory
-    fn compact(&mut self) -> Result<(), Box<Error>>{
-        let mut new_tree_file = try!(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(self.tree_file_path + ".new"));
+    /// Forces the WAL to sync to stable storage, regardless of the
+    /// configured durability policy.
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.wal_file.sync()
+    }
+
+    /// Looks up every value stored under `key`, in the memtable and on disk,
+    /// honoring any tombstones not yet folded away by `compact()`.
+    pub fn get(&self, key: &K) -> Result<Vec<V>, Box<dyn Error>> {
+        let mut values: BTreeSet<V> = self.mem_tree.get(key).into_iter().collect();
+
+        if ! self.deleted_keys.contains(key) {
+            let deleted_for_key = self.deleted_values.get(key);
+
+            for kv in (self.tree_file.range(key.clone()..=key.clone()))? {
+                if ! deleted_for_key.contains(&kv.value) {
+                    values.insert(kv.value);
+                }
+            }
+        }
+
+        Ok(values.into_iter().collect())
+    }
+
+    /// Returns every entry whose key falls within `bounds`, in ascending
+    /// key order, lazily merging the memtable with the on-disk tree and
+    /// honoring any tombstones not yet folded away by `compact()`.
+    pub fn range(&self, bounds: RangeInclusive<K>) -> Result<BTreeRangeIterator<'_, K, V>, Box<dyn Error>> {
+        let mem_iter = self.mem_tree.range(bounds.clone()).peekable();
+        let disk_iter = TombstoneFilter{inner: (self.tree_file.range(bounds))?, deleted_keys: &self.deleted_keys, deleted_values: &self.deleted_values}.peekable();
+
+        Ok(MergeIterator{mem_iter: mem_iter, disk_iter: disk_iter})
+    }
+
+    /// Merges the records in memory with the records on disk: a sorted
+    /// k-way merge of `mem_tree` and `tree_file`, produced lazily by
+    /// `CompactMergeIterator` rather than buffered up front, is streamed
+    /// into `tree_file` as a new, appended root, and the WAL/memtable are
+    /// reset to empty.
+    pub fn compact(&mut self) -> Result<(), Box<dyn Error>> {
+        let mem_iter = self.mem_tree.iter().peekable();
+        let disk_iter = TombstoneFilter{inner: (self.tree_file.iter())?, deleted_keys: &self.deleted_keys, deleted_values: &self.deleted_values}.peekable();
+
+        let merged = CompactMergeIterator{mem_iter: mem_iter, disk_iter: disk_iter};
+
+        // streams the merge straight into freshly-appended leaves and then
+        // commits the new root; fsyncs before returning, so by the time we
+        // truncate the WAL below its contents are already durable on disk --
+        // this is the sync boundary a batched (non-sync_every_write) WAL
+        // relies on instead of syncing on every write
+        (self.tree_file.compact_from_sorted(merged))?;
+
+        (self.wal_file.truncate())?;
+        self.mem_tree.clear();
+
+        // every tombstone has now either suppressed its matching disk entry
+        // or had nothing left to suppress, so none of them are needed anymore
+        self.deleted_keys.clear();
+        self.deleted_values.clear();
+
+        Ok(())
+    }
+}
+
+/// Lazily k-way merges a memtable-side iterator with the tombstone-filtered
+/// disk iterator, in ascending `(key, value)` order. An identical `(key,
+/// value)` pair present in both is only yielded once, preferring the
+/// memtable's copy since it is the newer write. Shared by `compact()`
+/// (merging the whole memtable with the whole tree, via
+/// `CompactMergeIterator`) and `BTree::range` (merging a bounded slice of
+/// each, via `BTreeRangeIterator`), so the tie-breaking logic only lives
+/// in one place.
+pub struct MergeIterator<'a, K: 'a + KeyType, V: 'a + ValueType, M: Iterator<Item = KeyValuePair<K, V>>> {
+    mem_iter: Peekable<M>,
+    disk_iter: Peekable<TombstoneFilter<'a, K, V, OnDiskBTreeIterator<K, V>>>,
+}
+
+impl<'a, K: 'a + KeyType, V: 'a + ValueType, M: Iterator<Item = KeyValuePair<K, V>>> Iterator for MergeIterator<'a, K, V, M> {
+    type Item = KeyValuePair<K, V>;
+
+    fn next(&mut self) -> Option<KeyValuePair<K, V>> {
+        let take_mem = match (self.mem_iter.peek(), self.disk_iter.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some(m), Some(d)) => {
+                if m.key != d.key {
+                    m.key < d.key
+                } else if m.value != d.value {
+                    // same key, distinct values: both are kept, smaller value first
+                    m.value < d.value
+                } else {
+                    // exact (key, value) duplicate: the memtable's copy is newer
+                    true
+                }
+            },
+        };
+
+        if take_mem {
+            let item = self.mem_iter.next().unwrap();
+
+            // drop the disk copy of an exact duplicate, since the memtable wins
+            if self.disk_iter.peek().is_some_and(|d| d.key == item.key && d.value == item.value) {
+                self.disk_iter.next();
+            }
+
+            Some(item)
+        } else {
+            self.disk_iter.next()
+        }
+    }
+}
+
+/// `MergeIterator` over the whole memtable, for `compact()` to stream
+/// straight into `OnDiskBTree::compact_from_sorted` without ever buffering
+/// the merged sequence.
+type CompactMergeIterator<'a, K, V> = MergeIterator<'a, K, V, MultiMapIterator<'a, K, V>>;
+
+/// Filters a `KeyValuePair` iterator down to the entries not covered by a
+/// whole-key or single-value tombstone. Used to hide disk entries that a
+/// delete recorded in the WAL has not yet physically removed.
+struct TombstoneFilter<'a, K: 'a + KeyType, V: 'a + ValueType, I: Iterator<Item = KeyValuePair<K, V>>> {
+    inner: I,
+    deleted_keys: &'a BTreeSet<K>,
+    deleted_values: &'a MultiMap<K, V>,
+}
+
+impl<'a, K: 'a + KeyType, V: 'a + ValueType, I: Iterator<Item = KeyValuePair<K, V>>> Iterator for TombstoneFilter<'a, K, V, I> {
+    type Item = KeyValuePair<K, V>;
 
-        let mut mem_iter = self.mem_tree.iter().fuse();  // get an iterator that always returns None when done
+    fn next(&mut self) -> Option<KeyValuePair<K, V>> {
+        for item in self.inner.by_ref() {
+            if self.deleted_keys.contains(&item.key) {
+                continue;
+            }
 
-        loop {
-            let mem_item = mem_iter.next();
+            if self.deleted_values.get(&item.key).contains(&item.value) {
+                continue;
+            }
 
+            return Some(item);
         }
+
+        None
     }
-*/
 }
 
+/// Walks a `BTree::range` query, lazily merging the memtable with the
+/// on-disk tree in ascending key order. Distinct values stored under the
+/// same key on either side are all yielded, and an identical `(key, value)`
+/// pair present in both is only yielded once.
+pub type BTreeRangeIterator<'a, K, V> = MergeIterator<'a, K, V, MultiMapRangeIterator<'a, K, V>>;
+
 
 #[cfg(test)]
 mod tests {
     use std::fs;
-    use std::fs::{OpenOptions, Metadata};
-    use ::BTree;
+    use std::fs::OpenOptions;
+    use std::io::Read;
+    use std::os::unix::fs::FileExt;
+    use ::{BTree, Config, KeyValuePair, MemtableLimit};
     use rand::{thread_rng, Rng};
 
 
     pub fn gen_temp_name() -> String {
         let file_name: String = thread_rng().gen_ascii_chars().take(10).collect();
 
-        return String::from("/tmp/") + &file_name + &String::from(".btr");
+        String::from("/tmp/") + &file_name + &String::from(".btr")
     }
 
     fn remove_files(file_path: String) {
-        fs::remove_file(&file_path);
-        fs::remove_file(file_path + ".wal");
+        let _ = fs::remove_file(&file_path);
+        let _ = fs::remove_file(file_path + ".wal");
+    }
+
+
+    #[test]
+    fn get_sees_memtable_and_disk() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<u8, u8>::new(file_path.to_owned(), 1, 1).unwrap();
+
+        btree.insert(2, 3).unwrap();
+        btree.insert(2, 4).unwrap();
+        btree.compact().unwrap();
+        btree.insert(2, 5).unwrap();
+
+        assert_eq!(btree.get(&2).unwrap(), vec![3, 4, 5]);
+        assert_eq!(btree.get(&9).unwrap(), Vec::<u8>::new());
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn range_merges_memtable_and_disk_in_order() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<u8, u8>::new(file_path.to_owned(), 1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(3, 1).unwrap();
+        btree.compact().unwrap();
+        btree.insert(2, 1).unwrap();
+        btree.insert(3, 1).unwrap(); // duplicate of a value already on disk
+
+        let results: Vec<KeyValuePair<u8, u8>> = btree.range(1..=3).unwrap().collect();
+
+        assert_eq!(results, vec![
+            KeyValuePair{key: 1, value: 1},
+            KeyValuePair{key: 2, value: 1},
+            KeyValuePair{key: 3, value: 1},
+        ]);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn delete_and_compact_remove_tombstoned_entries() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<u8, u8>::new(file_path.to_owned(), 1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(2, 3).unwrap();
+        btree.insert(2, 4).unwrap();
+        btree.compact().unwrap();
+
+        // whole-key tombstone: key 1 should disappear entirely
+        btree.delete(1).unwrap();
+
+        // single-value tombstone: only value 3 under key 2 should disappear
+        btree.delete_value(2, 3).unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), Vec::<u8>::new());
+        assert_eq!(btree.get(&2).unwrap(), vec![4]);
+
+        btree.compact().unwrap();
+
+        // the tombstones have now been folded away, and so have they themselves
+        assert!(btree.deleted_keys.is_empty());
+        assert!(btree.deleted_values.is_empty());
+
+        let on_disk: Vec<KeyValuePair<u8, u8>> = btree.tree_file.iter().unwrap().collect();
+
+        assert_eq!(on_disk, vec![
+            KeyValuePair{key: 2, value: 4},
+        ]);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn with_config_syncs_every_write_and_flush_succeeds() {
+        let file_path = gen_temp_name();
+
+        let config = Config{use_fsync: false, sync_every_write: true};
+        let mut btree = BTree::<u8, u8>::with_config(file_path.to_owned(), 1, 1, config).unwrap();
+
+        btree.insert(2, 3).unwrap(); // synced immediately under this config
+        btree.flush().unwrap(); // a no-op here, but must succeed regardless of policy
+
+        assert_eq!(btree.get(&2).unwrap(), vec![3]);
+
+        remove_files(file_path); // remove files assuming it all went well
     }
 
+    #[test]
+    fn with_memtable_limit_compacts_automatically() {
+        let file_path = gen_temp_name();
+
+        let limit = MemtableLimit{max_entries: Some(2), max_estimated_bytes: None};
+        let mut btree = BTree::<u8, u8>::with_memtable_limit(file_path.to_owned(), 1, 1, Config::default(), limit).unwrap();
+
+        assert_eq!(btree.memtable_len(), 0);
+
+        btree.insert(1, 1).unwrap();
+        assert_eq!(btree.memtable_len(), 1);
+        assert!(! btree.mem_tree.is_empty());
+
+        // this crosses the 2-entry threshold and should trigger an implicit compact()
+        btree.insert(2, 2).unwrap();
+
+        assert!(btree.mem_tree.is_empty());
+        assert_eq!(btree.memtable_len(), 0);
+        assert_eq!(btree.get(&1).unwrap(), vec![1]);
+        assert_eq!(btree.get(&2).unwrap(), vec![2]);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
 
     #[test]
     fn new_blank_file() {
@@ -141,6 +499,64 @@ mod tests {
         remove_files(file_path); // remove files assuming it all went well
     }
 
+    #[test]
+    fn wal_recovers_up_to_torn_tail() {
+        let file_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<String, String>::new(file_path.to_owned(), 15, 15).unwrap();
+
+            btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+            btree.insert("Foo".to_owned(), "Bar".to_owned()).unwrap();
+        }
+
+        // cut off the last byte of the WAL, as a crash mid-write would
+        let wal_path = file_path.to_owned() + ".wal";
+        let full_len = fs::metadata(&wal_path).unwrap().len();
+        let wal = OpenOptions::new().write(true).open(&wal_path).unwrap();
+        wal.set_len(full_len - 1).unwrap();
+
+        let btree = BTree::<String, String>::new(file_path.to_owned(), 15, 15).unwrap();
+
+        // the first record, verified in full, survives; the torn second record does not
+        assert!(btree.mem_tree.contains_key(&"Hello".to_owned()));
+        assert!(! btree.mem_tree.contains_key(&"Foo".to_owned()));
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn wal_rejects_out_of_range_length_without_allocating() {
+        let file_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<String, String>::new(file_path.to_owned(), 15, 15).unwrap();
+
+            btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+            btree.insert("Foo".to_owned(), "Bar".to_owned()).unwrap();
+        }
+
+        // stomp the second record's `length` field with a value no torn
+        // write could legitimately produce: bigger than a whole block
+        let wal_path = file_path.to_owned() + ".wal";
+
+        let mut reader = OpenOptions::new().read(true).open(&wal_path).unwrap();
+        let mut header = [0u8; 9];
+        reader.read_exact(&mut header).unwrap();
+        let first_record_size = 9 + u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+
+        let wal = OpenOptions::new().write(true).open(&wal_path).unwrap();
+        wal.write_at(&0xffff_ffffu32.to_le_bytes(), first_record_size + 4).unwrap();
+
+        let btree = BTree::<String, String>::new(file_path.to_owned(), 15, 15).unwrap();
+
+        // the first record, untouched, survives; the corrupted second record does not
+        assert!(btree.mem_tree.contains_key(&"Hello".to_owned()));
+        assert!(! btree.mem_tree.contains_key(&"Foo".to_owned()));
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
     #[test]
     fn new_existing_file() {
         let file_path = gen_temp_name();
@@ -164,14 +580,101 @@ mod tests {
 
         let mut btree = BTree::<u8, u8>::new(file_path.to_owned(), 1, 1).unwrap();
 
-        let len = btree.insert(2, 3).unwrap(); // insert into a new file
+        btree.insert(2, 3).unwrap(); // insert into a new file
 
-        assert!(btree.wal_file.len().unwrap() == 2);
+        // a single Full fragment: a 9-byte header, plus the op tag and the
+        // bincode-encoded key and value
+        assert!(btree.wal_file.len().unwrap() == 12);
         assert!(btree.mem_tree.contains_key(&2));
 
         remove_files(file_path); // remove files assuming it all went well
     }
 
+    #[test]
+    fn compact_folds_memtable_into_tree_file() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<u8, u8>::new(file_path.to_owned(), 1, 1).unwrap();
+
+        btree.insert(2, 3).unwrap();
+        btree.insert(1, 9).unwrap();
+        btree.insert(2, 4).unwrap();
+
+        btree.compact().unwrap();
+
+        // the memtable and WAL are reset once everything has been folded in
+        assert!(btree.mem_tree.is_empty());
+        assert!(btree.wal_file.is_new().unwrap());
+
+        let on_disk: Vec<KeyValuePair<u8, u8>> = btree.tree_file.iter().unwrap().collect();
+
+        assert_eq!(on_disk, vec![
+            KeyValuePair{key: 1, value: 9},
+            KeyValuePair{key: 2, value: 3},
+            KeyValuePair{key: 2, value: 4},
+        ]);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn compact_builds_multi_level_tree_from_streamed_entries() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<u16, u16>::new(file_path.to_owned(), 2, 2).unwrap();
+
+        // enough entries that the streamed internal-node levels built by
+        // compact_from_sorted have to cascade past the first level (more
+        // than NUM_CHILDREN leaves', i.e. NUM_CHILDREN^2 keys' worth),
+        // exercising the carry-propagation logic and yielding a tree three
+        // levels deep: leaves, one internal level, and the root
+        let count: u16 = 1100;
+
+        for key in 0..count {
+            btree.insert(key, key).unwrap();
+        }
+
+        btree.compact().unwrap();
+
+        let on_disk: Vec<KeyValuePair<u16, u16>> = btree.tree_file.iter().unwrap().collect();
+
+        assert_eq!(on_disk.len(), count as usize);
+        assert!(on_disk.windows(2).all(|w| w[0].key < w[1].key));
+        assert_eq!(on_disk[0].key, 0);
+        assert_eq!(on_disk[on_disk.len() - 1].key, count - 1);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn tree_file_recovers_prior_root_after_torn_commit_page() {
+        let file_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<u8, u8>::new(file_path.to_owned(), 1, 1).unwrap();
+
+            btree.insert(1, 9).unwrap();
+            btree.compact().unwrap();
+
+            btree.insert(2, 3).unwrap();
+            btree.compact().unwrap();
+        }
+
+        // cut off the last byte of the second commit page, as a crash
+        // mid-write would: the backward scan should skip past it and fall
+        // back to the still-intact first commit page
+        let full_len = fs::metadata(&file_path).unwrap().len();
+        let tree_file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        tree_file.set_len(full_len - 1).unwrap();
+
+        let btree = BTree::<u8, u8>::new(file_path.to_owned(), 1, 1).unwrap();
+        let on_disk: Vec<KeyValuePair<u8, u8>> = btree.tree_file.iter().unwrap().collect();
+
+        assert_eq!(on_disk, vec![KeyValuePair{key: 1, value: 9}]);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
     #[test]
     fn insert_new_str() {
         let file_path = gen_temp_name();
@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map;
+use std::collections::btree_set;
+use std::ops::RangeInclusive;
+
+use {KeyType, ValueType};
+use wal_file::KeyValuePair;
+
+/// An in-memory ordered multi-map: a key may be associated with more than
+/// one distinct value, and both keys and the values under a key are kept
+/// in ascending order so the whole thing can be walked as a single sorted
+/// stream of `KeyValuePair`s (the shape `compact()` needs to merge it
+/// against the on-disk tree).
+pub struct MultiMap<K: KeyType, V: ValueType> {
+    map: BTreeMap<K, BTreeSet<V>>,
+    len: usize,
+}
+
+impl<K: KeyType, V: ValueType> MultiMap<K, V> {
+    pub fn new() -> MultiMap<K, V> {
+        MultiMap{map: BTreeMap::new(), len: 0}
+    }
+
+    /// Inserts a value under `key`, returning `true` if it was not already present.
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        let inserted = self.map.entry(key).or_default().insert(value);
+
+        if inserted {
+            self.len += 1;
+        }
+
+        inserted
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn get(&self, key: &K) -> Vec<V> {
+        match self.map.get(key) {
+            Some(values) => values.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes every value stored under `key`.
+    pub fn remove_key(&mut self, key: &K) {
+        if let Some(values) = self.map.remove(key) {
+            self.len -= values.len();
+        }
+    }
+
+    /// Removes a single `(key, value)` pair, returning `true` if it was present.
+    pub fn remove_value(&mut self, key: &K, value: &V) -> bool {
+        let (removed, now_empty) = match self.map.get_mut(key) {
+            Some(values) => (values.remove(value), values.is_empty()),
+            None => (false, false),
+        };
+
+        if removed {
+            self.len -= 1;
+        }
+
+        if now_empty {
+            self.map.remove(key);
+        }
+
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Drops every entry, returning the map to a freshly-created state.
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.len = 0;
+    }
+
+    /// Returns an iterator over every `(key, value)` pair in ascending order.
+    pub fn iter(&self) -> MultiMapIterator<'_, K, V> {
+        MultiMapIterator{outer: self.map.iter(), current: None}
+    }
+
+    /// Returns an iterator over every `(key, value)` pair whose key falls
+    /// within `bounds`, in ascending order.
+    pub fn range(&self, bounds: RangeInclusive<K>) -> MultiMapRangeIterator<'_, K, V> {
+        MultiMapRangeIterator{outer: self.map.range(bounds), current: None}
+    }
+}
+
+/// Walks a `MultiMap` in ascending `(key, value)` order, yielding one
+/// `KeyValuePair` per distinct value.
+pub struct MultiMapIterator<'a, K: 'a + KeyType, V: 'a + ValueType> {
+    outer: btree_map::Iter<'a, K, BTreeSet<V>>,
+    current: Option<(&'a K, btree_set::Iter<'a, V>)>,
+}
+
+impl<'a, K: 'a + KeyType, V: 'a + ValueType> Iterator for MultiMapIterator<'a, K, V> {
+    type Item = KeyValuePair<K, V>;
+
+    fn next(&mut self) -> Option<KeyValuePair<K, V>> {
+        loop {
+            if let Some((key, ref mut values)) = self.current {
+                if let Some(value) = values.next() {
+                    return Some(KeyValuePair{key: key.clone(), value: value.clone()});
+                }
+            }
+
+            match self.outer.next() {
+                Some((key, values)) => self.current = Some((key, values.iter())),
+                None => return None,
+            }
+        }
+    }
+}
+
+/// Walks a bounded slice of a `MultiMap` in ascending `(key, value)` order.
+pub struct MultiMapRangeIterator<'a, K: 'a + KeyType, V: 'a + ValueType> {
+    outer: btree_map::Range<'a, K, BTreeSet<V>>,
+    current: Option<(&'a K, btree_set::Iter<'a, V>)>,
+}
+
+impl<'a, K: 'a + KeyType, V: 'a + ValueType> Iterator for MultiMapRangeIterator<'a, K, V> {
+    type Item = KeyValuePair<K, V>;
+
+    fn next(&mut self) -> Option<KeyValuePair<K, V>> {
+        loop {
+            if let Some((key, ref mut values)) = self.current {
+                if let Some(value) = values.next() {
+                    return Some(KeyValuePair{key: key.clone(), value: value.clone()});
+                }
+            }
+
+            match self.outer.next() {
+                Some((key, values)) => self.current = Some((key, values.iter())),
+                None => return None,
+            }
+        }
+    }
+}
+
+impl<'a, K: 'a + KeyType, V: 'a + ValueType> IntoIterator for &'a MultiMap<K, V> {
+    type Item = KeyValuePair<K, V>;
+    type IntoIter = MultiMapIterator<'a, K, V>;
+
+    fn into_iter(self) -> MultiMapIterator<'a, K, V> {
+        self.iter()
+    }
+}
@@ -0,0 +1,325 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode_into, decode_from};
+use rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
+
+use {KeyType, ValueType};
+
+/// A single key/value record, shared by the WAL, the memtable and the
+/// on-disk tree as the common currency they all merge and replay.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyValuePair<K: KeyType, V: ValueType> {
+    pub key: K,
+    pub value: V,
+}
+
+impl<K: KeyType, V: ValueType> Encodable for KeyValuePair<K, V> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("KeyValuePair", 2, |s| {
+            (s.emit_struct_field("key", 0, |s| self.key.encode(s)))?;
+            (s.emit_struct_field("value", 1, |s| self.value.encode(s)))?;
+
+            Ok(())
+        })
+    }
+}
+
+impl<K: KeyType, V: ValueType> Decodable for KeyValuePair<K, V> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<KeyValuePair<K, V>, D::Error> {
+        d.read_struct("KeyValuePair", 2, |d| {
+            let key = (d.read_struct_field("key", 0, |d| K::decode(d)))?;
+            let value = (d.read_struct_field("value", 1, |d| V::decode(d)))?;
+
+            Ok(KeyValuePair{key: key, value: value})
+        })
+    }
+}
+
+/// A WAL record: either a put of a `(key, value)` pair, or a tombstone
+/// recording a delete so replay can reconstruct the memtable exactly as
+/// it stood before a restart.
+pub enum WALRecord<K: KeyType, V: ValueType> {
+    Put(KeyValuePair<K, V>),
+    DeleteKey(K),
+    DeleteValue(KeyValuePair<K, V>),
+}
+
+const OP_PUT: u8 = 0;
+const OP_DELETE_KEY: u8 = 1;
+const OP_DELETE_VALUE: u8 = 2;
+
+/// The log is divided into fixed-size blocks; every physical record fragment
+/// lives entirely within one block, so a reader can always find the next
+/// fragment's header without scanning past a torn write.
+const BLOCK_SIZE: u64 = 32 * 1024;
+
+/// `crc32` (4 bytes) + `length` (4 bytes) + `type` (1 byte).
+const HEADER_SIZE: usize = 9;
+
+const TYPE_FULL: u8 = 1;
+const TYPE_FIRST: u8 = 2;
+const TYPE_MIDDLE: u8 = 3;
+const TYPE_LAST: u8 = 4;
+
+/// Encodes a `WALRecord` into its op-tagged bincode payload, the logical
+/// record that `write_record` then splits into physical block fragments.
+fn encode_record_payload<K: KeyType, V: ValueType>(record: &WALRecord<K, V>, size_limit: SizeLimit) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut payload = Vec::new();
+
+    match *record {
+        WALRecord::Put(ref kv) => {
+            (payload.write_all(&[OP_PUT]))?;
+            (encode_into(kv, &mut payload, size_limit))?;
+        },
+        WALRecord::DeleteKey(ref key) => {
+            (payload.write_all(&[OP_DELETE_KEY]))?;
+            (encode_into(key, &mut payload, size_limit))?;
+        },
+        WALRecord::DeleteValue(ref kv) => {
+            (payload.write_all(&[OP_DELETE_VALUE]))?;
+            (encode_into(kv, &mut payload, size_limit))?;
+        },
+    }
+
+    Ok(payload)
+}
+
+/// The inverse of `encode_record_payload`, given a reassembled logical record.
+fn decode_record_payload<K: KeyType, V: ValueType>(payload: &[u8]) -> Option<WALRecord<K, V>> {
+    if payload.is_empty() {
+        return None;
+    }
+
+    let (op, mut body) = payload.split_at(1);
+
+    match op[0] {
+        OP_PUT => decode_from(&mut body, SizeLimit::Infinite).ok().map(WALRecord::Put),
+        OP_DELETE_KEY => decode_from(&mut body, SizeLimit::Infinite).ok().map(WALRecord::DeleteKey),
+        OP_DELETE_VALUE => decode_from(&mut body, SizeLimit::Infinite).ok().map(WALRecord::DeleteValue),
+        _ => None,
+    }
+}
+
+/// CRC-32 (IEEE 802.3) of `data`, used to detect a torn write in a fragment.
+fn crc32(data: &[u8]) -> u32 {
+    const POLY: u32 = 0xedb8_8320;
+
+    let mut crc: u32 = 0xffff_ffff;
+
+    for &byte in data {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (POLY & mask);
+        }
+    }
+
+    !crc
+}
+
+/// Write-ahead log backing a `BTree`'s in-memory state: every mutation is
+/// appended here before it touches `mem_tree`, so the memtable can be
+/// reconstructed by replaying the file from the start after a restart.
+///
+/// Records are framed as a sequence of fixed `BLOCK_SIZE` blocks. A record
+/// that fits in what's left of the current block is written whole as a
+/// `Full` fragment; a larger record is split across blocks into `First`,
+/// zero or more `Middle`, and a `Last` fragment. Each fragment carries its
+/// own CRC so a crash mid-write corrupts at most the record being written,
+/// not the log as a whole.
+pub struct WALFile<K: KeyType, V: ValueType> {
+    file: File,
+    max_key_size: usize,
+    max_value_size: usize,
+    len: u64,
+    block_offset: u64,
+    use_fsync: bool,
+    sync_every_write: bool,
+    _marker: ::std::marker::PhantomData<(K, V)>,
+}
+
+impl<K: KeyType, V: ValueType> WALFile<K, V> {
+    pub fn new(file_path: String, max_key_size: usize, max_value_size: usize, use_fsync: bool, sync_every_write: bool) -> Result<WALFile<K, V>, Box<dyn Error>> {
+        let file = (OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&file_path))?;
+        let len = (file.metadata())?.len();
+        let block_offset = len % BLOCK_SIZE;
+
+        Ok(WALFile{file: file, max_key_size: max_key_size, max_value_size: max_value_size, len: len, block_offset: block_offset, use_fsync: use_fsync, sync_every_write: sync_every_write, _marker: ::std::marker::PhantomData})
+    }
+
+    /// Appends a record to the end of the log, splitting it into one or
+    /// more CRC-framed fragments that each fit within a single block.
+    pub fn write_record(&mut self, record: &WALRecord<K, V>) -> Result<(), Box<dyn Error>> {
+        let size_limit = SizeLimit::Bounded((self.max_key_size + self.max_value_size) as u64);
+        let payload = (encode_record_payload(record, size_limit))?;
+
+        (self.file.seek(SeekFrom::End(0)))?;
+
+        let mut written = 0usize;
+
+        loop {
+            // not enough room left in this block for even a header: zero-fill
+            // the rest of it so the reader knows to skip straight to the next one
+            if BLOCK_SIZE - self.block_offset < HEADER_SIZE as u64 {
+                let pad = (BLOCK_SIZE - self.block_offset) as usize;
+                (self.file.write_all(&vec![0u8; pad]))?;
+                self.block_offset = 0;
+            }
+
+            let available = (BLOCK_SIZE - self.block_offset) as usize - HEADER_SIZE;
+            let remaining = payload.len() - written;
+            let take = ::std::cmp::min(available, remaining);
+
+            let record_type = match (written == 0, written + take == payload.len()) {
+                (true, true) => TYPE_FULL,
+                (true, false) => TYPE_FIRST,
+                (false, true) => TYPE_LAST,
+                (false, false) => TYPE_MIDDLE,
+            };
+
+            let fragment = &payload[written..written + take];
+
+            (self.file.write_all(&crc32(fragment).to_le_bytes()))?;
+            (self.file.write_all(&(take as u32).to_le_bytes()))?;
+            (self.file.write_all(&[record_type]))?;
+            (self.file.write_all(fragment))?;
+
+            self.block_offset += (HEADER_SIZE + take) as u64;
+            written += take;
+
+            if written >= payload.len() {
+                break;
+            }
+        }
+
+        self.len = (self.file.metadata())?.len();
+
+        if self.sync_every_write {
+            (self.sync())?;
+        }
+
+        Ok(())
+    }
+
+    /// Syncs the log to stable storage, using `sync_all` (data and metadata)
+    /// if `use_fsync` is set, or the lighter `sync_data` otherwise.
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.use_fsync {
+            (self.file.sync_all())?;
+        } else {
+            (self.file.sync_data())?;
+        }
+
+        Ok(())
+    }
+
+    /// The size, in bytes, of every record written so far.
+    pub fn len(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(self.len)
+    }
+
+    /// `true` if the log is empty: nothing has been written to it since it
+    /// was created or last truncated.
+    pub fn is_new(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.len == 0)
+    }
+
+    /// Truncates the log back to empty, e.g. once its contents have been
+    /// folded into the on-disk tree by `compact()`.
+    pub fn truncate(&mut self) -> Result<(), Box<dyn Error>> {
+        (self.file.set_len(0))?;
+        (self.file.seek(SeekFrom::Start(0)))?;
+
+        self.len = 0;
+        self.block_offset = 0;
+
+        Ok(())
+    }
+}
+
+/// Replays every record in a `WALFile`, in the order it was written.
+///
+/// Each fragment's CRC is recomputed as it is read; the first CRC mismatch
+/// or truncated fragment (a torn tail left by a crash mid-write) ends the
+/// replay cleanly, treating everything verified so far as the recovered
+/// state rather than failing the whole read.
+pub struct WALIterator<'a, K: 'a + KeyType, V: 'a + ValueType> {
+    file: &'a mut File,
+    block_offset: u64,
+    _marker: ::std::marker::PhantomData<(K, V)>,
+}
+
+impl<'a, K: 'a + KeyType, V: 'a + ValueType> Iterator for WALIterator<'a, K, V> {
+    type Item = WALRecord<K, V>;
+
+    fn next(&mut self) -> Option<WALRecord<K, V>> {
+        let mut payload: Vec<u8> = Vec::new();
+
+        loop {
+            if BLOCK_SIZE - self.block_offset < HEADER_SIZE as u64 {
+                let skip = (BLOCK_SIZE - self.block_offset) as i64;
+
+                if self.file.seek(SeekFrom::Current(skip)).is_err() {
+                    return None;
+                }
+
+                self.block_offset = 0;
+            }
+
+            let mut header = [0u8; HEADER_SIZE];
+
+            if self.file.read_exact(&mut header).is_err() {
+                return None;
+            }
+
+            let stored_crc = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            let record_type = header[8];
+
+            // a torn write can leave `length` holding garbage; bound it against
+            // what could possibly remain in this block before trusting it with
+            // an allocation, and treat an out-of-range value as a torn record
+            let max_length = (BLOCK_SIZE - self.block_offset) as usize - HEADER_SIZE;
+
+            if length > max_length {
+                return None;
+            }
+
+            let mut fragment = vec![0u8; length];
+
+            if self.file.read_exact(&mut fragment).is_err() {
+                return None;
+            }
+
+            if crc32(&fragment) != stored_crc {
+                return None;
+            }
+
+            self.block_offset += (HEADER_SIZE + length) as u64;
+            payload.extend_from_slice(&fragment);
+
+            match record_type {
+                TYPE_FULL | TYPE_LAST => break,
+                TYPE_FIRST | TYPE_MIDDLE => continue,
+                _ => return None,
+            }
+        }
+
+        decode_record_payload(&payload)
+    }
+}
+
+impl<'a, K: 'a + KeyType, V: 'a + ValueType> IntoIterator for &'a mut WALFile<K, V> {
+    type Item = WALRecord<K, V>;
+    type IntoIter = WALIterator<'a, K, V>;
+
+    fn into_iter(self) -> WALIterator<'a, K, V> {
+        self.file.seek(SeekFrom::Start(0)).expect("seek to start of WAL");
+
+        WALIterator{file: &mut self.file, block_offset: 0, _marker: ::std::marker::PhantomData}
+    }
+}